@@ -1,12 +1,162 @@
 use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::sync::Arc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error};
 
+#[allow(dead_code)]
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
+#[allow(dead_code)]
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Default for `ServerConfig::vote_rpc_timeout`.
+const VOTE_RPC_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Default for `ServerConfig::max_in_flight_handlers`.
+const MAX_IN_FLIGHT_HANDLERS: usize = 256;
+
+/// Returns `false` to refuse a vote Raft would otherwise grant.
+type VoteFilter = Arc<dyn Fn(&VoteRequest) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+struct ServerConfig {
+    /// How long a candidate waits on a single peer's vote before giving up
+    /// on it.
+    vote_rpc_timeout: Duration,
+    /// Consulted on every vote request. It can only turn a grant into a
+    /// denial, never the other way round, so it can't break election safety.
+    vote_filter: Option<VoteFilter>,
+    /// How many command handlers may run at once; must be at least 1. Past
+    /// that, up to as many commands again wait for a free slot and the rest
+    /// are dropped, which the sender sees as a closed `resp`. The loop keeps
+    /// taking commands either way, so a `ShutDown` is never stuck behind
+    /// them.
+    max_in_flight_handlers: usize,
+    vote_fan_out: VoteFanOut,
+    /// Where role and term transitions are recorded for later forensics.
+    audit_sink: Option<Arc<dyn AuditSink>>,
+}
+
+/// How a candidate spreads its vote requests over its peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VoteFanOut {
+    /// Ask every voting peer at once.
+    All,
+    /// Ask this many peers at a time, and only move on to the next wave
+    /// once the current one has answered or timed out without a majority.
+    #[allow(dead_code)]
+    Waves(usize),
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            vote_rpc_timeout: VOTE_RPC_TIMEOUT,
+            vote_filter: None,
+            max_in_flight_handlers: MAX_IN_FLIGHT_HANDLERS,
+            vote_fan_out: VoteFanOut::All,
+            audit_sink: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("vote_rpc_timeout", &self.vote_rpc_timeout)
+            .field("vote_filter", &self.vote_filter.is_some())
+            .field("max_in_flight_handlers", &self.max_in_flight_handlers)
+            .field("vote_fan_out", &self.vote_fan_out)
+            .field("audit_sink", &self.audit_sink.is_some())
+            .finish()
+    }
+}
+
+/// A role or term transition, as handed to an `AuditSink`.
+#[derive(Clone, Debug, PartialEq)]
+enum AuditEvent {
+    Term {
+        node_id: i64,
+        old_term: i64,
+        new_term: i64,
+        cause: TermChange,
+    },
+    Role {
+        node_id: i64,
+        term: i64,
+        old_role: ElectionState,
+        new_role: ElectionState,
+    },
+}
+
+/// Records every role and term transition. This is an audit trail for
+/// post-incident analysis, not part of replication: it's called with the
+/// `State` lock held, so it should be quick.
+trait AuditSink: Send + Sync {
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Appends one line per event to a file, with the wall-clock time in
+/// milliseconds since the epoch. `record` only queues the line; a writer
+/// thread appends and syncs it, so nothing waits on the disk with the
+/// `State` lock held. Dropping the sink waits for queued lines to be written.
+struct FileAuditSink {
+    lines: Option<std::sync::mpsc::Sender<String>>,
+    writer: Option<std::thread::JoinHandle<()>>,
+}
+
+#[allow(dead_code)]
+impl FileAuditSink {
+    fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        use std::io::Write;
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (lines, queued) = std::sync::mpsc::channel::<String>();
+        let writer = std::thread::spawn(move || {
+            for line in queued {
+                let written = writeln!(file, "{}", line).and_then(|_| file.sync_data());
+                if let Err(err) = written {
+                    error!(error = %err, "failed to record audit event");
+                }
+            }
+        });
+        Ok(FileAuditSink {
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        if let Some(lines) = &self.lines {
+            let _ = lines.send(format!("{} {:?}", at, event));
+        }
+    }
+}
+
+impl Drop for FileAuditSink {
+    fn drop(&mut self) {
+        // Closing the channel ends the writer once it has drained it.
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 enum ElectionState {
     Follower,
@@ -14,11 +164,40 @@ enum ElectionState {
     Leader,
 }
 
+/// Result of a `StepDown` command.
+#[derive(Clone, Debug, PartialEq)]
+enum StepDownOutcome {
+    /// The node was leader and is now a follower in `term`.
+    SteppedDown { term: i64 },
+    /// The node wasn't leader; it reports the state it was in instead.
+    NotLeader(ElectionState),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TermChange {
+    ElectionStarted,
+    HigherTermSeen,
+    VoteGranted,
+}
+
+/// Per-node Raft state.
+///
+/// This is the only lock a handler takes. It must never be held across an
+/// `.await` on another node (or, once they exist, on storage or the state
+/// machine): two nodes campaigning at once would otherwise each sit on their
+/// own lock while waiting for the other's vote.
 #[derive(Debug)]
 struct State {
     election_state: ElectionState,
     current_term: i64,
     voted_for: Option<i64>,
+    /// Term of the election this node is still tallying, if any. A second
+    /// trigger for the same term is dropped rather than starting another
+    /// term on top of it.
+    campaign_term: Option<i64>,
+    /// What this node does in elections. Only a voter campaigns.
+    kind: NodeKind,
+    config: ServerConfig,
 }
 
 struct Server {
@@ -26,7 +205,43 @@ struct Server {
     state: Arc<Mutex<State>>,
     rx: Receiver<Command>,
     tx: Sender<Command>,
+    nodes: PeerRegistry,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NodeKind {
+    Voter,
+    /// Votes and counts toward quorum but never campaigns, so it can never
+    /// become leader.
+    Witness,
+    /// Reachable, but neither asked for votes nor counted toward quorum.
+    #[allow(dead_code)]
+    Learner,
+    /// A permanent read-only replica: like a learner, but never promoted.
+    /// It denies every vote and never campaigns.
+    ReadReplica,
+}
+
+#[derive(Clone, Debug)]
+struct PeerInfo {
+    tx: Sender<Command>,
+    kind: NodeKind,
+}
+
+/// Who is in the cluster besides this node, and how to reach them. Quorum
+/// is always computed from here, so a membership change only has to update
+/// the registry.
+#[derive(Clone, Debug, Default)]
+struct PeerRegistry {
+    peers: std::collections::BTreeMap<i64, PeerInfo>,
+}
+
+/// A fully connected group of servers in one process, talking over their
+/// command channels. Every node's loop runs on the current runtime.
+struct Cluster {
     nodes: Vec<Sender<Command>>,
+    states: Vec<Arc<Mutex<State>>>,
+    tasks: JoinSet<()>,
 }
 
 #[derive(Clone)]
@@ -39,6 +254,23 @@ struct VoteRequest {
 struct VoteResponse {
     term: i64,
     vote_granted: bool,
+    /// Why the vote was denied; `None` when it was granted.
+    #[allow(dead_code)]
+    deny_reason: Option<VoteDenial>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VoteDenial {
+    /// The request was our own, looped back to us.
+    OwnRequest,
+    /// The candidate's term is behind ours.
+    StaleTerm,
+    /// We already voted for someone else in this term.
+    AlreadyVoted,
+    /// This node's kind doesn't vote.
+    NotAVoter,
+    /// `ServerConfig::vote_filter` refused it.
+    Filtered,
 }
 
 enum Command {
@@ -46,170 +278,732 @@ enum Command {
         request: VoteRequest,
         resp: oneshot::Sender<VoteResponse>,
     },
+    #[allow(dead_code)]
     RequestVote {},
-    ShutDown {},
+    /// Starts an election right away and answers whether this node won.
+    /// There is no pre-vote: without heartbeats no node can tell that a
+    /// leader is alive, so this deposes a working leader whenever a majority
+    /// answers. It only backs off when a peer is already in a newer term.
+    #[allow(dead_code)]
+    ForceElection { resp: oneshot::Sender<bool> },
+    #[allow(dead_code)]
+    StepDown {
+        resp: oneshot::Sender<StepDownOutcome>,
+    },
+    /// Stops the node. If `resp` is given, it is answered once every handler
+    /// has stopped and the node has let go of its state and its channel.
+    ShutDown { resp: Option<oneshot::Sender<()>> },
+}
+
+impl State {
+    /// Moves to a newer term. Terms only ever go up: a node adopts the
+    /// highest term it has seen, so whichever leader holds the highest term
+    /// is the one the rest of the cluster converges on.
+    fn set_term(&mut self, id: i64, term: i64, cause: TermChange) {
+        debug_assert!(
+            term > self.current_term,
+            "term went from {} to {}",
+            self.current_term,
+            term
+        );
+        debug!(
+            node_id = id,
+            old_term = self.current_term,
+            new_term = term,
+            cause = ?cause,
+            "term changed"
+        );
+        self.audit(AuditEvent::Term {
+            node_id: id,
+            old_term: self.current_term,
+            new_term: term,
+            cause,
+        });
+        self.current_term = term;
+    }
+
+    fn set_role(&mut self, id: i64, role: ElectionState) {
+        if role != self.election_state {
+            self.audit(AuditEvent::Role {
+                node_id: id,
+                term: self.current_term,
+                old_role: self.election_state.clone(),
+                new_role: role.clone(),
+            });
+        }
+        self.election_state = role;
+    }
+
+    fn audit(&self, event: AuditEvent) {
+        if let Some(audit_sink) = &self.config.audit_sink {
+            audit_sink.record(&event);
+        }
+    }
+
+    /// Gives up leadership without touching the term, so any peer can win
+    /// the next election.
+    fn step_down(&mut self, id: i64) -> StepDownOutcome {
+        if self.election_state != ElectionState::Leader {
+            return StepDownOutcome::NotLeader(self.election_state.clone());
+        }
+        self.set_role(id, ElectionState::Follower);
+        StepDownOutcome::SteppedDown {
+            term: self.current_term,
+        }
+    }
+
+    /// Decides on a vote request. The check of `voted_for` and the term
+    /// update happen under a single borrow, so callers must hold the `State`
+    /// lock for the whole call.
+    ///
+    /// | request term | `voted_for`      | decision                     |
+    /// |--------------|------------------|------------------------------|
+    /// | any          | (request is ours)| deny: `OwnRequest`           |
+    /// | lower        | any              | deny: `StaleTerm`            |
+    /// | equal        | someone else     | deny: `AlreadyVoted`         |
+    /// | equal        | `None`           | grant, record the vote       |
+    /// | equal        | the candidate    | grant again (retried request)|
+    /// | higher       | any              | step down, adopt term, grant |
+    ///
+    /// Where the table grants, a node whose kind doesn't vote denies with
+    /// `NotAVoter`, and `ServerConfig::vote_filter` can deny with `Filtered`.
+    /// The response always carries our term after the decision. The table
+    /// itself lives in `decide_vote`; this applies its outcome.
+    fn vote(&mut self, id: i64, vote_request: &VoteRequest) -> VoteResponse {
+        let candidate_id = vote_request.candidate_id;
+        let refusal = match &self.config.vote_filter {
+            _ if !self.kind.votes() => Some(VoteDenial::NotAVoter),
+            Some(vote_filter) if !vote_filter(vote_request) => Some(VoteDenial::Filtered),
+            _ => None,
+        };
+        let VoteDecision {
+            term,
+            voted_for,
+            term_change,
+            decision,
+        } = decide_vote(id, self.current_term, self.voted_for, refusal, vote_request);
+        self.voted_for = voted_for;
+        if let Some(cause) = term_change {
+            self.set_term(id, term, cause);
+            self.set_role(id, ElectionState::Follower);
+        }
+
+        if let Err(reason) = decision {
+            debug!(
+                node_id = id,
+                candidate_id,
+                term = vote_request.term,
+                reason = ?reason,
+                "vote denied"
+            );
+        }
+        VoteResponse {
+            term: self.current_term,
+            vote_granted: decision.is_ok(),
+            deny_reason: decision.err(),
+        }
+    }
+}
+
+/// The outcome of `decide_vote`: the `State` fields after the decision and
+/// whether the vote was granted.
+#[derive(Debug, PartialEq)]
+struct VoteDecision {
+    term: i64,
+    voted_for: Option<i64>,
+    /// Set when the request's term is adopted; the node then follows.
+    term_change: Option<TermChange>,
+    decision: std::result::Result<(), VoteDenial>,
+}
+
+/// The decision table of `State::vote`, free of locks, logging and auditing.
+/// `refusal` is why this node would refuse a vote it could otherwise grant.
+///
+/// There is no log yet, so the candidate's log is not compared.
+fn decide_vote(
+    id: i64,
+    current_term: i64,
+    voted_for: Option<i64>,
+    refusal: Option<VoteDenial>,
+    vote_request: &VoteRequest,
+) -> VoteDecision {
+    use std::cmp::Ordering::*;
+
+    let candidate_id = vote_request.candidate_id;
+    let unchanged = |decision| VoteDecision {
+        term: current_term,
+        voted_for,
+        term_change: None,
+        decision,
+    };
+    match (vote_request.term.cmp(&current_term), voted_for) {
+        // Our own request looped back through a misconfigured peer list.
+        // We already voted for ourselves; granting again would double-count.
+        _ if candidate_id == id => unchanged(Err(VoteDenial::OwnRequest)),
+        (Less, _) => unchanged(Err(VoteDenial::StaleTerm)),
+        (Equal, Some(voted_for)) if voted_for != candidate_id => {
+            unchanged(Err(VoteDenial::AlreadyVoted))
+        }
+        // Either a retry from the candidate we voted for, or a term we
+        // learned from someone else's message without voting in it yet.
+        (Equal, _) => match refusal {
+            Some(refusal) => unchanged(Err(refusal)),
+            None => VoteDecision {
+                voted_for: Some(candidate_id),
+                ..unchanged(Ok(()))
+            },
+        },
+        // The term is adopted even when the vote is refused.
+        (Greater, _) => match refusal {
+            Some(refusal) => VoteDecision {
+                term: vote_request.term,
+                voted_for: None,
+                term_change: Some(TermChange::HigherTermSeen),
+                decision: Err(refusal),
+            },
+            None => VoteDecision {
+                term: vote_request.term,
+                voted_for: Some(candidate_id),
+                term_change: Some(TermChange::VoteGranted),
+                decision: Ok(()),
+            },
+        },
+    }
 }
 
+#[allow(dead_code)]
 impl Server {
     fn new(id: i64) -> Self {
+        Server::build(id, NodeKind::Voter, ServerConfig::default())
+    }
+
+    fn with_config(id: i64, config: ServerConfig) -> Self {
+        Server::build(id, NodeKind::Voter, config)
+    }
+
+    /// A node that only votes. See `NodeKind::Witness`.
+    fn witness(id: i64) -> Self {
+        Server::build(id, NodeKind::Witness, ServerConfig::default())
+    }
+
+    /// A node that never votes or campaigns. See `NodeKind::ReadReplica`.
+    fn read_replica(id: i64) -> Self {
+        Server::build(id, NodeKind::ReadReplica, ServerConfig::default())
+    }
+
+    fn build(id: i64, kind: NodeKind, config: ServerConfig) -> Self {
+        assert!(
+            config.max_in_flight_handlers > 0,
+            "max_in_flight_handlers must be at least 1"
+        );
         let (tx, rx) = mpsc::channel(32);
         Server {
             state: Arc::new(Mutex::new(State {
                 election_state: ElectionState::Follower,
                 current_term: 0,
                 voted_for: None,
+                campaign_term: None,
+                kind,
+                config,
             })),
             id,
             rx,
             tx,
-            nodes: vec![],
+            nodes: PeerRegistry::default(),
+        }
+    }
+
+    /// Adds a voting peer.
+    fn add_node(&mut self, id: i64, node: Sender<Command>) {
+        self.add_peer(id, node, NodeKind::Voter)
+    }
+
+    /// Adds a peer of the given kind. Our own id or sender is dropped: a
+    /// node must never count itself as a peer.
+    fn add_peer(&mut self, id: i64, node: Sender<Command>, kind: NodeKind) {
+        if id == self.id || node.same_channel(&self.tx) {
+            return;
+        }
+        self.nodes.insert(id, PeerInfo { tx: node, kind })
+    }
+
+    fn add_nodes(&mut self, nodes: Vec<(i64, Sender<Command>)>) {
+        for (id, node) in nodes {
+            self.add_node(id, node)
+        }
+    }
+}
+
+impl NodeKind {
+    /// Whether a node of this kind grants votes and counts toward quorum.
+    fn votes(self) -> bool {
+        match self {
+            NodeKind::Voter | NodeKind::Witness => true,
+            NodeKind::Learner | NodeKind::ReadReplica => false,
+        }
+    }
+}
+
+impl PeerRegistry {
+    fn insert(&mut self, id: i64, peer: PeerInfo) {
+        self.peers.insert(id, peer);
+    }
+
+    #[allow(dead_code)]
+    fn remove(&mut self, id: i64) -> Option<PeerInfo> {
+        self.peers.remove(&id)
+    }
+
+    #[allow(dead_code)]
+    fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Every peer's channel, whatever its kind.
+    #[allow(dead_code)]
+    fn senders(&self) -> impl Iterator<Item = &Sender<Command>> {
+        self.peers.values().map(|peer| &peer.tx)
+    }
+
+    /// Channels of the peers whose votes count.
+    fn voters(&self) -> impl Iterator<Item = &Sender<Command>> {
+        self.peers
+            .values()
+            .filter(|peer| peer.kind.votes())
+            .map(|peer| &peer.tx)
+    }
+
+    /// Votes needed for a majority of the voting members, this node
+    /// included.
+    fn quorum(&self) -> usize {
+        self.voters().count().div_ceil(2) + 1
+    }
+}
+
+#[cfg(test)]
+impl Server {
+    /// Puts the node straight into the given role, term and vote, so tests
+    /// can start from states an election wouldn't easily produce.
+    async fn force_state(
+        &self,
+        election_state: ElectionState,
+        current_term: i64,
+        voted_for: Option<i64>,
+    ) {
+        let mut state = self.state.lock().await;
+        state.election_state = election_state;
+        state.current_term = current_term;
+        state.voted_for = voted_for;
+    }
+
+    /// The node's loop, for a test to drive alongside its own steps. It
+    /// takes the node's receiver, so it can run only once, but leaves `tx`,
+    /// `nodes` and `state` in place for the test to use.
+    fn run(&mut self) -> impl std::future::Future<Output = ()> {
+        let (_, closed) = mpsc::channel(1);
+        let rx = std::mem::replace(&mut self.rx, closed);
+        start(rx, self.id, self.nodes.clone(), self.state.clone())
+    }
+}
+
+#[allow(dead_code)]
+impl Cluster {
+    /// Builds `size` servers with ids `0..size` and spawns their loops.
+    /// Must be called from within a tokio runtime.
+    fn new(size: i64) -> Self {
+        Cluster::with_config(size, ServerConfig::default())
+    }
+
+    /// Like `new`, with every node using `config`.
+    fn with_config(size: i64, config: ServerConfig) -> Self {
+        let mut servers: Vec<Server> = (0..size)
+            .map(|id| Server::with_config(id, config.clone()))
+            .collect();
+        let nodes: Vec<Sender<Command>> = servers.iter().map(|s| s.tx.clone()).collect();
+        for server in &mut servers {
+            let peers = nodes
+                .iter()
+                .enumerate()
+                .map(|(id, node)| (id as i64, node.clone()))
+                .collect();
+            server.add_nodes(peers);
         }
+
+        let states = servers.iter().map(|s| s.state.clone()).collect();
+        let mut tasks = JoinSet::new();
+        for server in servers {
+            tasks.spawn(start(server.rx, server.id, server.nodes, server.state));
+        }
+        Cluster {
+            nodes,
+            states,
+            tasks,
+        }
+    }
+
+    /// The command channel of the node with the given id, if there is one.
+    fn node(&self, id: i64) -> Option<&Sender<Command>> {
+        self.nodes.get(usize::try_from(id).ok()?)
     }
 
-    fn add_node(&mut self, node: Sender<Command>) {
-        self.nodes.push(node)
+    /// The node that currently believes it leads in the highest term, if any.
+    async fn leader(&self) -> Option<i64> {
+        let mut leader = None;
+        let mut leader_term = -1;
+        for (id, state) in self.states.iter().enumerate() {
+            let state = state.lock().await;
+            if state.election_state == ElectionState::Leader && state.current_term > leader_term {
+                leader = Some(id as i64);
+                leader_term = state.current_term;
+            }
+        }
+        leader
     }
 
-    fn add_nodes(&mut self, nodes: Vec<Sender<Command>>) {
-        for node in nodes {
-            self.add_node(node)
+    /// Stops every node and waits for their loops to return.
+    async fn shut_down(mut self) {
+        for node in &self.nodes {
+            let _ = node.send(Command::ShutDown { resp: None }).await;
         }
+        while self.tasks.join_next().await.is_some() {}
     }
 }
 
 async fn start(
     mut rx: Receiver<Command>,
     id: i64,
-    nodes: Vec<Sender<Command>>,
+    nodes: PeerRegistry,
     self_state: Arc<Mutex<State>>,
 ) {
     use Command::*;
 
     println!("Starting Server id {}", id);
+    let max_in_flight_handlers = self_state.lock().await.config.max_in_flight_handlers;
+    // Commands that arrived while every handler slot was taken.
+    let mut pending = VecDeque::new();
+    // Handlers run as their own tasks so a slow one never holds up the loop,
+    // but they're joined here so a panic is reported instead of lost, and
+    // aborted on shutdown so none outlive the node.
+    let mut handlers = JoinSet::new();
     loop {
-        match rx.recv().await {
-            Some(cmd) => match cmd {
-                RequestVote {} => {
-                    let self_state = self_state.clone();
-                    let nodes = nodes.clone();
-                    tokio::spawn(async move {
-                        request_vote(id, nodes, self_state).await;
-                    });
+        let cmd = tokio::select! {
+            biased;
+            Some(result) = handlers.join_next() => {
+                if let Err(err) = result {
+                    if err.is_panic() {
+                        error!(node_id = id, error = %err, "command handler panicked");
+                    }
                 }
-                Vote { request, resp } => {
-                    let self_state = self_state.clone();
-                    tokio::spawn(async move {
-                        let res = process_vote_request(self_state, &request).await;
-                        match resp.send(res) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
-                    });
+                if let Some(cmd) = pending.pop_front() {
+                    dispatch(&mut handlers, cmd, id, &nodes, &self_state);
                 }
-                ShutDown { .. } => {
-                    return;
+                continue;
+            }
+            cmd = rx.recv() => cmd,
+        };
+        match cmd {
+            Some(ShutDown { resp }) => {
+                handlers.shutdown().await;
+                drop(pending);
+                drop(self_state);
+                drop(rx);
+                if let Some(resp) = resp {
+                    let _ = resp.send(());
                 }
-            },
+                return;
+            }
+            Some(cmd) if handlers.len() < max_in_flight_handlers => {
+                dispatch(&mut handlers, cmd, id, &nodes, &self_state);
+            }
+            Some(cmd) if pending.len() < max_in_flight_handlers => pending.push_back(cmd),
+            Some(_) => debug!(node_id = id, "handler queue full, command dropped"),
             None => {
+                handlers.shutdown().await;
                 return;
             }
         }
     }
 }
 
-async fn request_vote(id: i64, nodes: Vec<Sender<Command>>, self_state: Arc<Mutex<State>>) {
-    let mut self_state = self_state.lock().await;
-    self_state.election_state = ElectionState::Candidate;
-    self_state.current_term += 1;
-    self_state.voted_for = Option::from(id);
+/// Spawns the handler for `cmd` onto `handlers`. `ShutDown` is handled by
+/// the loop itself.
+fn dispatch(
+    handlers: &mut JoinSet<()>,
+    cmd: Command,
+    id: i64,
+    nodes: &PeerRegistry,
+    self_state: &Arc<Mutex<State>>,
+) {
+    use Command::*;
+
+    let self_state = self_state.clone();
+    match cmd {
+        RequestVote {} => {
+            let nodes = nodes.clone();
+            handlers.spawn(async move {
+                request_vote(id, nodes, self_state, None).await;
+            });
+        }
+        ForceElection { resp } => {
+            let nodes = nodes.clone();
+            handlers.spawn(async move {
+                request_vote(id, nodes, self_state, Some(resp)).await;
+            });
+        }
+        StepDown { resp } => {
+            handlers.spawn(async move {
+                let outcome = self_state.lock().await.step_down(id);
+                let _ = resp.send(outcome);
+            });
+        }
+        Vote { request, resp } => {
+            handlers.spawn(async move {
+                let res = process_vote_request(id, self_state, &request).await;
+                let _ = resp.send(res);
+            });
+        }
+        ShutDown { .. } => unreachable!("ShutDown is handled by the loop"),
+    }
+    debug!(
+        node_id = id,
+        in_flight = handlers.len(),
+        "command handler started"
+    );
+}
+
+/// Runs one election and reports on `resp` whether this node won. After a
+/// win it keeps listening to the peers that hadn't answered yet, and steps
+/// down if one of them is already in a newer term.
+async fn request_vote(
+    id: i64,
+    nodes: PeerRegistry,
+    self_state: Arc<Mutex<State>>,
+    resp: Option<oneshot::Sender<bool>>,
+) {
+    let report = |won| {
+        if let Some(resp) = resp {
+            let _ = resp.send(won);
+        }
+    };
+    let (vote_request, vote_rpc_timeout, vote_fan_out) = {
+        let mut self_state = self_state.lock().await;
+        if self_state.kind != NodeKind::Voter
+            || self_state.campaign_term == Some(self_state.current_term)
+        {
+            report(false);
+            return;
+        }
+        let term = self_state.current_term + 1;
+        self_state.set_term(id, term, TermChange::ElectionStarted);
+        self_state.set_role(id, ElectionState::Candidate);
+        self_state.voted_for = Option::from(id);
+        self_state.campaign_term = Some(term);
 
-    let vote_request = VoteRequest {
-        term: self_state.current_term,
-        candidate_id: id,
+        let vote_request = VoteRequest {
+            term: self_state.current_term,
+            candidate_id: id,
+        };
+        let config = &self_state.config;
+        (vote_request, config.vote_rpc_timeout, config.vote_fan_out)
     };
 
     let mut voted = 0;
+    let quorum = nodes.quorum();
+    let wave_size = match vote_fan_out {
+        VoteFanOut::All => usize::MAX,
+        VoteFanOut::Waves(wave_size) => wave_size.max(1),
+    };
+    let ask = |node: Sender<Command>| {
+        let request = vote_request.clone();
+        timeout(vote_rpc_timeout, async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let cmd = Command::Vote {
+                request,
+                resp: resp_tx,
+            };
+            node.send(cmd).await.ok()?;
+            resp_rx.await.ok()
+        })
+    };
 
+    let mut peers = nodes.voters();
     let mut receivers = FuturesUnordered::new();
-    for node in &nodes {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::Vote {
-            request: vote_request.clone(),
-            resp: resp_tx,
-        };
-        receivers.push(resp_rx);
-        node.send(cmd).await;
-    }
-
-    while let Some(result) = receivers.next().await {
-        match result {
-            Ok(vote_response) => {
+    let won = 'election: {
+        // The state lock is released while waiting on peers, so every
+        // response is checked against the state as it is now: another
+        // handler may have moved this node to a newer term in the meantime.
+        loop {
+            // Once we have a majority the rest can't change the outcome.
+            if voted + 1 >= quorum {
+                break;
+            }
+            if receivers.is_empty() {
+                receivers.extend(peers.by_ref().take(wave_size).cloned().map(ask));
+            }
+            let result = match receivers.next().await {
+                Some(result) => result,
+                None => break,
+            };
+            if let Ok(Some(vote_response)) = result {
+                let mut self_state = self_state.lock().await;
+                // A newer term is adopted even if this election is already
+                // over for us.
                 if vote_response.term > self_state.current_term {
-                    self_state.election_state = ElectionState::Follower;
-                    self_state.current_term = vote_response.term;
-                    return;
+                    self_state.voted_for = None;
+                    self_state.set_term(id, vote_response.term, TermChange::HigherTermSeen);
+                    self_state.set_role(id, ElectionState::Follower);
+                    break 'election false;
+                }
+
+                if self_state.current_term != vote_request.term
+                    || self_state.election_state != ElectionState::Candidate
+                {
+                    break 'election false;
                 }
 
                 if vote_response.vote_granted {
                     voted += 1
                 }
             }
-            Err(_) => {}
         }
+
+        let mut self_state = self_state.lock().await;
+        if self_state.campaign_term == Some(vote_request.term) {
+            self_state.campaign_term = None;
+        }
+        if self_state.current_term != vote_request.term
+            || self_state.election_state != ElectionState::Candidate
+        {
+            break 'election false;
+        }
+
+        // A majority of the voting members, counting our own vote.
+        if voted + 1 >= quorum {
+            self_state.set_role(id, ElectionState::Leader);
+            break 'election true;
+        }
+
+        false
+    };
+    report(won);
+    if !won {
+        return;
     }
 
-    if voted >= nodes.len() / 2 {
-        self_state.election_state = ElectionState::Leader
+    // Same-term answers can't change anything now, but a peer already in a
+    // newer term means this leadership is stale.
+    while let Some(result) = receivers.next().await {
+        if let Ok(Some(vote_response)) = result {
+            if vote_response.term > vote_request.term {
+                let mut self_state = self_state.lock().await;
+                if vote_response.term > self_state.current_term {
+                    self_state.voted_for = None;
+                    self_state.set_term(id, vote_response.term, TermChange::HigherTermSeen);
+                    self_state.set_role(id, ElectionState::Follower);
+                }
+                return;
+            }
+        }
     }
 }
 
 async fn process_vote_request(
+    id: i64,
     self_state: Arc<Mutex<State>>,
     vote_request: &VoteRequest,
 ) -> VoteResponse {
-    let mut vote_granted = false;
-    let mut term = 0;
-    let mut self_state = self_state.lock().await;
+    self_state.lock().await.vote(id, vote_request)
+}
 
-    if vote_request.term < self_state.current_term {
-        term = self_state.current_term;
-        vote_granted = false;
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+    use tokio::time::sleep;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
 
-    if vote_request.term == self_state.current_term {
-        match self_state.voted_for {
-            Some(id) => {
-                if id == vote_request.candidate_id {
-                    vote_granted = true;
-                    term = vote_request.term;
-                } else {
-                    vote_granted = false;
-                }
+    #[derive(Debug, Default, PartialEq)]
+    struct TermEvent {
+        node_id: i64,
+        old_term: i64,
+        new_term: i64,
+        cause: String,
+    }
+
+    impl Visit for TermEvent {
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            match field.name() {
+                "node_id" => self.node_id = value,
+                "old_term" => self.old_term = value,
+                "new_term" => self.new_term = value,
+                _ => {}
             }
-            // Should probably never happen, but just in case...
-            None => {
-                self_state.voted_for = Option::from(vote_request.candidate_id);
-                self_state.current_term = vote_request.term;
-                vote_granted = true;
-                term = vote_request.term;
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "cause" {
+                self.cause = format!("{:?}", value);
             }
         }
     }
 
-    if vote_request.term > self_state.current_term {
-        self_state.voted_for = Option::from(vote_request.candidate_id);
-        self_state.current_term = vote_request.term;
-        vote_granted = true;
-        term = vote_request.term;
+    struct TermEvents(Arc<StdMutex<Vec<TermEvent>>>);
+
+    #[derive(Default)]
+    struct MemoryAuditSink(StdMutex<Vec<AuditEvent>>);
+
+    impl AuditSink for MemoryAuditSink {
+        fn record(&self, event: &AuditEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
     }
 
-    VoteResponse { term, vote_granted }
-}
+    impl<S: tracing::Subscriber> Layer<S> for TermEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            let mut term_event = TermEvent::default();
+            event.record(&mut term_event);
+            if !term_event.cause.is_empty() {
+                self.0.lock().unwrap().push(term_event);
+            }
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::time::{sleep, Duration};
+    #[derive(Debug, Default)]
+    struct ErrorEvent {
+        node_id: i64,
+        message: String,
+        error: String,
+    }
+
+    impl Visit for ErrorEvent {
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            if field.name() == "node_id" {
+                self.node_id = value;
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            match field.name() {
+                "message" => self.message = format!("{:?}", value),
+                "error" => self.error = format!("{:?}", value),
+                _ => {}
+            }
+        }
+    }
+
+    struct ErrorEvents(Arc<StdMutex<Vec<ErrorEvent>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for ErrorEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            if *event.metadata().level() == tracing::Level::ERROR {
+                let mut error_event = ErrorEvent::default();
+                event.record(&mut error_event);
+                self.0.lock().unwrap().push(error_event);
+            }
+        }
+    }
 
     #[tokio::test]
     async fn server_new() {
@@ -224,15 +1018,15 @@ mod tests {
     #[tokio::test]
     async fn request_vote_win_election() {
         let mut server = Server::new(0);
-        let node_1 = Server::new(1);
-        let node_2 = Server::new(2);
-        let node_3 = Server::new(3);
-        let node_4 = Server::new(4);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        let mut node_3 = Server::new(3);
+        let mut node_4 = Server::new(4);
         let nodes = vec![
-            node_1.tx.clone(),
-            node_2.tx.clone(),
-            node_3.tx.clone(),
-            node_4.tx.clone(),
+            (1, node_1.tx.clone()),
+            (2, node_2.tx.clone()),
+            (3, node_3.tx.clone()),
+            (4, node_4.tx.clone()),
         ];
         server.add_nodes(nodes);
         let state = server.state.clone();
@@ -241,49 +1035,1451 @@ mod tests {
         let cloned_tx = server.tx.clone();
         let shutdown = tokio::spawn(async move {
             sleep(Duration::from_millis(100)).await;
-            for node in nodes {
-                node.send(Command::ShutDown {}).await;
+            for node in nodes.senders() {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
             }
-            cloned_tx.send(Command::ShutDown {}).await;
+            let _ = cloned_tx.send(Command::ShutDown { resp: None }).await;
         });
 
-        tokio::join!(
+        let _ = tokio::join!(
             shutdown,
+            server.run(),
             server.tx.send(Command::RequestVote {}),
-            start(
-                server.rx,
-                server.id,
-                server.nodes.clone(),
-                server.state.clone()
-            ),
-            start(
-                node_1.rx,
-                node_1.id,
-                node_1.nodes.clone(),
-                node_1.state.clone()
-            ),
-            start(
-                node_2.rx,
-                node_2.id,
-                node_2.nodes.clone(),
-                node_2.state.clone()
-            ),
-            start(
-                node_3.rx,
-                node_3.id,
-                node_3.nodes.clone(),
-                node_3.state.clone()
-            ),
-            start(
-                node_4.rx,
-                node_4.id,
-                node_4.nodes.clone(),
-                node_4.state.clone()
-            ),
+            node_1.run(),
+            node_2.run(),
+            node_3.run(),
+            node_4.run(),
         );
 
         let server_state = state.lock().await;
         assert_eq!(ElectionState::Leader, server_state.election_state);
         assert_eq!(1, server_state.current_term);
     }
+
+    #[tokio::test]
+    async fn request_vote_logs_term_changes() {
+        let events = Arc::new(StdMutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(TermEvents(events.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        server.add_nodes(vec![(1, node_1.tx.clone()), (2, node_2.tx.clone())]);
+
+        let nodes = server.nodes.clone();
+        let cloned_tx = server.tx.clone();
+        let shutdown = tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            for node in nodes.senders() {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            let _ = cloned_tx.send(Command::ShutDown { resp: None }).await;
+        });
+
+        let _ = tokio::join!(
+            shutdown,
+            server.run(),
+            server.tx.send(Command::RequestVote {}),
+            node_1.run(),
+            node_2.run(),
+        );
+
+        let mut events = std::mem::take(&mut *events.lock().unwrap());
+        events.sort_by_key(|event| event.node_id);
+        let expected = vec![
+            TermEvent {
+                node_id: 0,
+                old_term: 0,
+                new_term: 1,
+                cause: "ElectionStarted".to_string(),
+            },
+            TermEvent {
+                node_id: 1,
+                old_term: 0,
+                new_term: 1,
+                cause: "VoteGranted".to_string(),
+            },
+            TermEvent {
+                node_id: 2,
+                old_term: 0,
+                new_term: 1,
+                cause: "VoteGranted".to_string(),
+            },
+        ];
+        assert_eq!(expected, events);
+    }
+
+    #[tokio::test]
+    async fn force_election_wins_on_healthy_cluster() {
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        server.add_nodes(vec![(1, node_1.tx.clone()), (2, node_2.tx.clone())]);
+
+        let tx = server.tx.clone();
+        let nodes = server.nodes.clone();
+        let force_election = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = resp_rx.await.unwrap();
+            for node in nodes.senders() {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            won
+        });
+
+        let (won, ..) = tokio::join!(force_election, server.run(), node_1.run(), node_2.run(),);
+
+        assert!(won.unwrap());
+        let server_state = server.state.lock().await;
+        assert_eq!(ElectionState::Leader, server_state.election_state);
+        assert_eq!(1, server_state.current_term);
+    }
+
+    #[tokio::test]
+    async fn force_election_backs_off_when_cluster_is_ahead() {
+        // Node 0 is still in term 0; the others are in term 5.
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        node_1.force_state(ElectionState::Leader, 5, Some(1)).await;
+        node_2
+            .force_state(ElectionState::Follower, 5, Some(1))
+            .await;
+        server.add_nodes(vec![(1, node_1.tx.clone()), (2, node_2.tx.clone())]);
+
+        let tx = server.tx.clone();
+        let nodes = server.nodes.clone();
+        let force_election = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = resp_rx.await.unwrap();
+            for node in nodes.senders() {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            won
+        });
+
+        let (won, ..) = tokio::join!(force_election, server.run(), node_1.run(), node_2.run(),);
+
+        assert!(!won.unwrap());
+        let server_state = server.state.lock().await;
+        assert_eq!(ElectionState::Follower, server_state.election_state);
+        assert_eq!(5, server_state.current_term);
+        assert_eq!(
+            ElectionState::Leader,
+            node_1.state.lock().await.election_state
+        );
+    }
+
+    #[tokio::test]
+    async fn force_election_deposes_a_live_leader() {
+        let cluster = Cluster::new(3);
+        for id in [0, 1] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = cluster
+                .node(id)
+                .unwrap()
+                .send(Command::ForceElection { resp: resp_tx })
+                .await;
+            assert!(resp_rx.await.unwrap());
+        }
+
+        // Node 1 was a healthy follower of node 0 in term 1.
+        assert_eq!(Some(1), cluster.leader().await);
+        let node_0 = cluster.states[0].lock().await;
+        assert_eq!(ElectionState::Follower, node_0.election_state);
+        assert_eq!(2, node_0.current_term);
+        drop(node_0);
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_vote_concludes_with_silent_peer() {
+        let mut server = Server::new(0);
+        // Already voted in term 1, so only the silent peer could make a
+        // majority and the election has to wait it out.
+        let mut node_1 = Server::new(1);
+        node_1
+            .force_state(ElectionState::Follower, 1, Some(2))
+            .await;
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_nodes(vec![(1, node_1.tx.clone()), (9, silent_tx)]);
+
+        let tx = server.tx.clone();
+        let node_1_tx = node_1.tx.clone();
+        let force_election = tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = timeout(VOTE_RPC_TIMEOUT * 4, resp_rx).await;
+            let elapsed = started.elapsed();
+            let _ = node_1_tx.send(Command::ShutDown { resp: None }).await;
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            (won, elapsed)
+        });
+
+        let (force_election, ..) = tokio::join!(force_election, server.run(), node_1.run(),);
+
+        let (won, elapsed) = force_election.unwrap();
+        assert!(!won.expect("election did not conclude").unwrap());
+        assert!(elapsed >= VOTE_RPC_TIMEOUT);
+        assert!(elapsed < VOTE_RPC_TIMEOUT * 2);
+        assert_eq!(
+            ElectionState::Candidate,
+            server.state.lock().await.election_state
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_vote_does_not_block_vote_handling() {
+        let mut server = Server::new(0);
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_node(9, silent_tx);
+
+        let tx = server.tx.clone();
+        let vote = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+
+            let started = tokio::time::Instant::now();
+            let (vote_tx, vote_rx) = oneshot::channel();
+            let request = VoteRequest {
+                term: 5,
+                candidate_id: 1,
+            };
+            let _ = tx
+                .send(Command::Vote {
+                    request,
+                    resp: vote_tx,
+                })
+                .await;
+            let vote_response = vote_rx.await.unwrap();
+            let elapsed = started.elapsed();
+
+            let _ = resp_rx.await;
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            (vote_response, elapsed)
+        });
+
+        let (vote, _) = tokio::join!(vote, server.run(),);
+
+        let (vote_response, elapsed) = vote.unwrap();
+        assert!(vote_response.vote_granted);
+        assert!(elapsed < VOTE_RPC_TIMEOUT);
+        let server_state = server.state.lock().await;
+        assert_eq!(ElectionState::Follower, server_state.election_state);
+        assert_eq!(5, server_state.current_term);
+    }
+
+    #[tokio::test]
+    async fn step_down_lets_another_node_win() {
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        server.add_nodes(vec![(1, node_1.tx.clone()), (2, node_2.tx.clone())]);
+        node_1.add_nodes(vec![(0, server.tx.clone()), (2, node_2.tx.clone())]);
+        node_2.add_nodes(vec![(0, server.tx.clone()), (1, node_1.tx.clone())]);
+
+        let tx = server.tx.clone();
+        let node_1_tx = node_1.tx.clone();
+        let node_2_tx = node_2.tx.clone();
+        let failover = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let first_won = resp_rx.await.unwrap();
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::StepDown { resp: resp_tx }).await;
+            let outcome = resp_rx.await.unwrap();
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = node_1_tx
+                .send(Command::ForceElection { resp: resp_tx })
+                .await;
+            let second_won = resp_rx.await.unwrap();
+
+            for node in &[&tx, &node_1_tx, &node_2_tx] {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            (first_won, outcome, second_won)
+        });
+
+        let (failover, ..) = tokio::join!(failover, server.run(), node_1.run(), node_2.run(),);
+
+        let (first_won, outcome, second_won) = failover.unwrap();
+        assert!(first_won);
+        assert_eq!(StepDownOutcome::SteppedDown { term: 1 }, outcome);
+        assert!(second_won);
+        let server_state = server.state.lock().await;
+        assert_eq!(ElectionState::Follower, server_state.election_state);
+        assert_eq!(2, server_state.current_term);
+        let node_1_state = node_1.state.lock().await;
+        assert_eq!(ElectionState::Leader, node_1_state.election_state);
+        assert_eq!(2, node_1_state.current_term);
+    }
+
+    #[tokio::test]
+    async fn step_down_on_follower_is_a_no_op() {
+        let mut server = Server::new(0);
+
+        let tx = server.tx.clone();
+        let step_down = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::StepDown { resp: resp_tx }).await;
+            let outcome = resp_rx.await.unwrap();
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            outcome
+        });
+
+        let (outcome, _) = tokio::join!(step_down, server.run(),);
+
+        assert_eq!(
+            StepDownOutcome::NotLeader(ElectionState::Follower),
+            outcome.unwrap()
+        );
+        assert_eq!(0, server.state.lock().await.current_term);
+    }
+
+    #[tokio::test]
+    async fn process_vote_request_regrants_retried_vote() {
+        let state = Arc::new(Mutex::new(State {
+            election_state: ElectionState::Follower,
+            current_term: 0,
+            voted_for: None,
+            campaign_term: None,
+            kind: NodeKind::Voter,
+            config: ServerConfig::default(),
+        }));
+        let request = VoteRequest {
+            term: 1,
+            candidate_id: 1,
+        };
+
+        let first = process_vote_request(0, state.clone(), &request).await;
+        let retried = process_vote_request(0, state.clone(), &request).await;
+        let other = VoteRequest {
+            term: 1,
+            candidate_id: 2,
+        };
+        let other = process_vote_request(0, state.clone(), &other).await;
+
+        assert!(first.vote_granted);
+        assert!(retried.vote_granted);
+        assert_eq!(first.term, retried.term);
+        assert!(!other.vote_granted);
+        let state = state.lock().await;
+        assert_eq!(1, state.current_term);
+        assert_eq!(Some(1), state.voted_for);
+    }
+
+    #[tokio::test]
+    async fn self_loop_does_not_count_as_a_vote() {
+        let mut server = Server::new(0);
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_nodes(vec![(0, server.tx.clone()), (9, silent_tx)]);
+        assert_eq!(1, server.nodes.len());
+        // Bypass add_node to check the receiving side ignores it too.
+        server.nodes.insert(
+            5,
+            PeerInfo {
+                tx: server.tx.clone(),
+                kind: NodeKind::Voter,
+            },
+        );
+
+        let tx = server.tx.clone();
+        let force_election = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = resp_rx.await.unwrap();
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            won
+        });
+
+        let (won, _) = tokio::join!(force_election, server.run(),);
+
+        assert!(!won.unwrap());
+        let server_state = server.state.lock().await;
+        assert_eq!(ElectionState::Candidate, server_state.election_state);
+        assert_eq!(Some(0), server_state.voted_for);
+    }
+
+    #[tokio::test]
+    async fn follower_in_high_term_denies_low_term_candidate() {
+        let mut server = Server::new(0);
+        server
+            .force_state(ElectionState::Follower, 7, Some(2))
+            .await;
+
+        let tx = server.tx.clone();
+        let vote = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let request = VoteRequest {
+                term: 3,
+                candidate_id: 1,
+            };
+            let _ = tx
+                .send(Command::Vote {
+                    request,
+                    resp: resp_tx,
+                })
+                .await;
+            let vote_response = resp_rx.await.unwrap();
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            vote_response
+        });
+
+        let (vote_response, _) = tokio::join!(vote, server.run(),);
+
+        let vote_response = vote_response.unwrap();
+        assert!(!vote_response.vote_granted);
+        assert_eq!(7, vote_response.term);
+        let server_state = server.state.lock().await;
+        assert_eq!(7, server_state.current_term);
+        assert_eq!(Some(2), server_state.voted_for);
+    }
+
+    #[tokio::test]
+    async fn back_to_back_triggers_run_one_election() {
+        let cluster = Cluster::new(3);
+
+        let (first_tx, first_rx) = oneshot::channel();
+        let (second_tx, second_rx) = oneshot::channel();
+        let _ = cluster
+            .node(0)
+            .unwrap()
+            .send(Command::ForceElection { resp: first_tx })
+            .await;
+        let _ = cluster
+            .node(0)
+            .unwrap()
+            .send(Command::ForceElection { resp: second_tx })
+            .await;
+        let first = first_rx.await.unwrap();
+        let second = second_rx.await.unwrap();
+
+        assert!(first != second, "exactly one trigger runs the election");
+        assert_eq!(Some(0), cluster.leader().await);
+        for state in &cluster.states {
+            assert_eq!(1, state.lock().await.current_term);
+        }
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test]
+    async fn witness_tips_election_but_never_leads() {
+        let mut server = Server::new(0);
+        let mut witness = Server::witness(1);
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_nodes(vec![(1, witness.tx.clone()), (9, silent_tx.clone())]);
+        witness.add_nodes(vec![(0, server.tx.clone()), (9, silent_tx)]);
+
+        let tx = server.tx.clone();
+        let witness_tx = witness.tx.clone();
+        let elections = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let server_won = resp_rx.await.unwrap();
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = witness_tx
+                .send(Command::ForceElection { resp: resp_tx })
+                .await;
+            let witness_won = resp_rx.await.unwrap();
+
+            let _ = witness_tx.send(Command::ShutDown { resp: None }).await;
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            (server_won, witness_won)
+        });
+
+        let (elections, ..) = tokio::join!(elections, server.run(), witness.run(),);
+
+        let (server_won, witness_won) = elections.unwrap();
+        assert!(server_won);
+        assert!(!witness_won);
+        assert_eq!(
+            ElectionState::Leader,
+            server.state.lock().await.election_state
+        );
+        let witness_state = witness.state.lock().await;
+        assert_eq!(ElectionState::Follower, witness_state.election_state);
+        assert_eq!(1, witness_state.current_term);
+        assert_eq!(Some(0), witness_state.voted_for);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_peers_are_abandoned_at_configured_vote_timeout() {
+        let vote_rpc_timeout = Duration::from_millis(10);
+        let mut server = Server::with_config(
+            0,
+            ServerConfig {
+                vote_rpc_timeout,
+                ..ServerConfig::default()
+            },
+        );
+        let mut node_1 = Server::new(1);
+        // Two peers that take vote requests but sit on the replies. With
+        // node 1 alone the candidate is one vote short, so it has to wait
+        // on them until they time out.
+        let mut peers = vec![(1, node_1.tx.clone())];
+        for id in [8, 9] {
+            let (slow_tx, mut slow_rx) = mpsc::channel(1);
+            tokio::spawn(async move {
+                let mut pending = vec![];
+                while let Some(cmd) = slow_rx.recv().await {
+                    pending.push(cmd);
+                }
+            });
+            peers.push((id, slow_tx));
+        }
+        server.add_nodes(peers);
+
+        let tx = server.tx.clone();
+        let node_1_tx = node_1.tx.clone();
+        let force_election = tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = resp_rx.await.unwrap();
+            let elapsed = started.elapsed();
+            let _ = node_1_tx.send(Command::ShutDown { resp: None }).await;
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            (won, elapsed)
+        });
+
+        let (force_election, ..) = tokio::join!(force_election, server.run(), node_1.run(),);
+
+        let (won, elapsed) = force_election.unwrap();
+        assert!(!won);
+        assert!(elapsed >= vote_rpc_timeout);
+        assert!(elapsed < VOTE_RPC_TIMEOUT);
+    }
+
+    #[test]
+    fn vote_decision_table() {
+        use VoteDenial::*;
+
+        // (request term, voted_for before, candidate) ->
+        // (deny reason, response term, voted_for after, election state after).
+        // The node is 0, a candidate in term 2.
+        let cases = vec![
+            (
+                2,
+                Some(0),
+                0,
+                (Some(OwnRequest), 2, Some(0), ElectionState::Candidate),
+            ),
+            (
+                1,
+                None,
+                1,
+                (Some(StaleTerm), 2, None, ElectionState::Candidate),
+            ),
+            (
+                1,
+                Some(1),
+                1,
+                (Some(StaleTerm), 2, Some(1), ElectionState::Candidate),
+            ),
+            (
+                1,
+                Some(2),
+                1,
+                (Some(StaleTerm), 2, Some(2), ElectionState::Candidate),
+            ),
+            (2, None, 1, (None, 2, Some(1), ElectionState::Candidate)),
+            (2, Some(1), 1, (None, 2, Some(1), ElectionState::Candidate)),
+            (
+                2,
+                Some(2),
+                1,
+                (Some(AlreadyVoted), 2, Some(2), ElectionState::Candidate),
+            ),
+            (3, None, 1, (None, 3, Some(1), ElectionState::Follower)),
+            (3, Some(1), 1, (None, 3, Some(1), ElectionState::Follower)),
+            (3, Some(2), 1, (None, 3, Some(1), ElectionState::Follower)),
+        ];
+
+        for (term, voted_for, candidate_id, expected) in cases {
+            let mut state = State {
+                election_state: ElectionState::Candidate,
+                current_term: 2,
+                voted_for,
+                campaign_term: None,
+                kind: NodeKind::Voter,
+                config: ServerConfig::default(),
+            };
+            let request = VoteRequest { term, candidate_id };
+            let response = state.vote(0, &request);
+            assert_eq!(response.deny_reason.is_none(), response.vote_granted);
+            assert_eq!(
+                expected,
+                (
+                    response.deny_reason,
+                    response.term,
+                    state.voted_for,
+                    state.election_state.clone()
+                ),
+                "term {}, voted_for {:?}, candidate {}",
+                term,
+                voted_for,
+                candidate_id
+            );
+        }
+    }
+
+    #[test]
+    fn decide_vote_covers_every_branch() {
+        use TermChange::*;
+        use VoteDenial::*;
+
+        // (request term, voted_for, candidate, refusal) ->
+        // (term, voted_for, term_change, decision) for node 0 in term 2.
+        let cases = vec![
+            ((2, Some(0), 0, None), (2, Some(0), None, Err(OwnRequest))),
+            ((3, None, 0, None), (2, None, None, Err(OwnRequest))),
+            ((1, None, 1, None), (2, None, None, Err(StaleTerm))),
+            ((1, Some(2), 1, None), (2, Some(2), None, Err(StaleTerm))),
+            (
+                (1, None, 1, Some(Filtered)),
+                (2, None, None, Err(StaleTerm)),
+            ),
+            ((2, Some(2), 1, None), (2, Some(2), None, Err(AlreadyVoted))),
+            (
+                (2, Some(2), 1, Some(NotAVoter)),
+                (2, Some(2), None, Err(AlreadyVoted)),
+            ),
+            ((2, None, 1, None), (2, Some(1), None, Ok(()))),
+            ((2, Some(1), 1, None), (2, Some(1), None, Ok(()))),
+            (
+                (2, None, 1, Some(NotAVoter)),
+                (2, None, None, Err(NotAVoter)),
+            ),
+            (
+                (2, Some(1), 1, Some(Filtered)),
+                (2, Some(1), None, Err(Filtered)),
+            ),
+            ((3, None, 1, None), (3, Some(1), Some(VoteGranted), Ok(()))),
+            (
+                (3, Some(2), 1, None),
+                (3, Some(1), Some(VoteGranted), Ok(())),
+            ),
+            (
+                (3, Some(2), 1, Some(Filtered)),
+                (3, None, Some(HigherTermSeen), Err(Filtered)),
+            ),
+            (
+                (3, None, 1, Some(NotAVoter)),
+                (3, None, Some(HigherTermSeen), Err(NotAVoter)),
+            ),
+        ];
+
+        for ((term, voted_for, candidate_id, refusal), (t, v, c, d)) in cases {
+            let request = VoteRequest { term, candidate_id };
+            assert_eq!(
+                decide_vote(0, 2, voted_for, refusal, &request),
+                VoteDecision {
+                    term: t,
+                    voted_for: v,
+                    term_change: c,
+                    decision: d,
+                },
+                "term {}, voted_for {:?}, candidate {}, refusal {:?}",
+                term,
+                voted_for,
+                candidate_id,
+                refusal
+            );
+        }
+    }
+
+    #[test]
+    fn vote_denied_by_kind_or_filter() {
+        let vote_filter: VoteFilter = Arc::new(|_: &VoteRequest| false);
+        let filtered = ServerConfig {
+            vote_filter: Some(vote_filter),
+            ..ServerConfig::default()
+        };
+        let cases = vec![
+            (
+                NodeKind::ReadReplica,
+                ServerConfig::default(),
+                VoteDenial::NotAVoter,
+            ),
+            (NodeKind::Voter, filtered, VoteDenial::Filtered),
+        ];
+
+        for (kind, config, expected) in cases {
+            let mut state = State {
+                election_state: ElectionState::Follower,
+                current_term: 2,
+                voted_for: None,
+                campaign_term: None,
+                kind,
+                config,
+            };
+            let request = VoteRequest {
+                term: 2,
+                candidate_id: 1,
+            };
+            let response = state.vote(0, &request);
+            assert!(!response.vote_granted);
+            assert_eq!(Some(expected), response.deny_reason);
+            assert_eq!(None, state.voted_for);
+        }
+    }
+
+    #[tokio::test]
+    async fn vote_filter_moves_leadership_elsewhere() {
+        let vote_filter: VoteFilter = Arc::new(|request: &VoteRequest| request.candidate_id != 0);
+        let config = ServerConfig {
+            vote_filter: Some(vote_filter),
+            ..ServerConfig::default()
+        };
+        let cluster = Cluster::with_config(3, config);
+
+        let mut won = vec![];
+        for id in [0, 1] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = cluster
+                .node(id)
+                .unwrap()
+                .send(Command::ForceElection { resp: resp_tx })
+                .await;
+            won.push(resp_rx.await.unwrap());
+        }
+
+        assert_eq!(vec![false, true], won);
+        assert_eq!(Some(1), cluster.leader().await);
+        assert_eq!(
+            ElectionState::Follower,
+            cluster.states[0].lock().await.election_state
+        );
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test]
+    async fn handler_panic_is_reported() {
+        let events = Arc::new(StdMutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(ErrorEvents(events.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let vote_filter: VoteFilter =
+            Arc::new(|_: &VoteRequest| -> bool { panic!("vote filter failed") });
+        let mut server = Server::with_config(
+            0,
+            ServerConfig {
+                vote_filter: Some(vote_filter),
+                ..ServerConfig::default()
+            },
+        );
+
+        let tx = server.tx.clone();
+        let vote = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let request = VoteRequest {
+                term: 1,
+                candidate_id: 1,
+            };
+            let _ = tx
+                .send(Command::Vote {
+                    request,
+                    resp: resp_tx,
+                })
+                .await;
+            let answered = resp_rx.await.is_ok();
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            answered
+        });
+
+        let (answered, _) = tokio::join!(vote, server.run(),);
+
+        assert!(!answered.unwrap());
+        let events = events.lock().unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(0, events[0].node_id);
+        assert_eq!("command handler panicked", events[0].message);
+        assert!(events[0].error.contains("vote filter failed"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shut_down_mid_election_stops_handlers() {
+        let mut server = Server::new(0);
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_node(9, silent_tx);
+
+        let tx = server.tx.clone();
+        let shut_down = tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            let answered = resp_rx.await.is_ok();
+            (answered, started.elapsed())
+        });
+
+        let (shut_down, _) = tokio::join!(shut_down, server.run(),);
+
+        let (answered, elapsed) = shut_down.unwrap();
+        assert!(!answered);
+        assert!(elapsed < VOTE_RPC_TIMEOUT);
+        // Only our own reference is left: no handler outlived the loop.
+        assert_eq!(1, Arc::strong_count(&server.state));
+    }
+
+    #[test]
+    fn quorum_follows_registry_membership() {
+        let mut registry = PeerRegistry::default();
+        for id in 1..=4 {
+            let (tx, _rx) = mpsc::channel(1);
+            let kind = NodeKind::Voter;
+            registry.insert(id, PeerInfo { tx, kind });
+        }
+        assert_eq!(3, registry.quorum());
+
+        let (tx, _rx) = mpsc::channel(1);
+        let kind = NodeKind::Learner;
+        registry.insert(5, PeerInfo { tx, kind });
+        assert_eq!(4, registry.voters().count());
+        assert_eq!(3, registry.quorum());
+
+        registry.remove(4);
+        registry.remove(3);
+        assert_eq!(2, registry.voters().count());
+        assert_eq!(2, registry.quorum());
+        assert_eq!(3, registry.senders().count());
+    }
+
+    #[tokio::test]
+    async fn read_replica_never_votes_or_campaigns() {
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut replica = Server::read_replica(2);
+        server.add_node(1, node_1.tx.clone());
+        server.add_peer(2, replica.tx.clone(), NodeKind::ReadReplica);
+        node_1.add_node(0, server.tx.clone());
+        node_1.add_peer(2, replica.tx.clone(), NodeKind::ReadReplica);
+        replica.add_nodes(vec![(0, server.tx.clone()), (1, node_1.tx.clone())]);
+
+        let tx = server.tx.clone();
+        let node_1_tx = node_1.tx.clone();
+        let replica_tx = replica.tx.clone();
+        let elections = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = replica_tx
+                .send(Command::ForceElection { resp: resp_tx })
+                .await;
+            let replica_won = resp_rx.await.unwrap();
+
+            let (vote_tx, vote_rx) = oneshot::channel();
+            let request = VoteRequest {
+                term: 1,
+                candidate_id: 0,
+            };
+            let _ = replica_tx
+                .send(Command::Vote {
+                    request,
+                    resp: vote_tx,
+                })
+                .await;
+            let replica_vote = vote_rx.await.unwrap();
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let server_won = resp_rx.await.unwrap();
+
+            for node in &[&tx, &node_1_tx, &replica_tx] {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            (replica_won, replica_vote, server_won)
+        });
+
+        let (elections, ..) = tokio::join!(elections, server.run(), node_1.run(), replica.run(),);
+
+        let (replica_won, replica_vote, server_won) = elections.unwrap();
+        assert!(!replica_won);
+        assert!(!replica_vote.vote_granted);
+        assert!(server_won);
+        assert_eq!(2, server.nodes.quorum());
+        let replica_state = replica.state.lock().await;
+        assert_eq!(ElectionState::Follower, replica_state.election_state);
+        assert_eq!(None, replica_state.voted_for);
+    }
+
+    /// The `in_flight` count of a "command handler started" event.
+    #[derive(Default)]
+    struct InFlight(Option<u64>);
+
+    impl Visit for InFlight {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            if field.name() == "in_flight" {
+                self.0 = Some(value);
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    struct InFlightEvents(Arc<StdMutex<Vec<u64>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for InFlightEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            let mut in_flight = InFlight::default();
+            event.record(&mut in_flight);
+            if let Some(count) = in_flight.0 {
+                self.0.lock().unwrap().push(count);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handler_flood_stays_within_limit() {
+        let in_flight = Arc::new(StdMutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(InFlightEvents(in_flight.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let config = ServerConfig {
+            max_in_flight_handlers: 2,
+            ..ServerConfig::default()
+        };
+        let mut server = Server::with_config(0, config);
+
+        let tx = server.tx.clone();
+        let state = server.state.clone();
+        let flood = tokio::spawn(async move {
+            // Let the loop read its config before we take the lock.
+            sleep(Duration::from_millis(1)).await;
+            // Holding the state lock parks every StepDown handler.
+            let guard = state.lock().await;
+            let mut replies = vec![];
+            for _ in 0..10 {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                let _ = tx.send(Command::StepDown { resp: resp_tx }).await;
+                replies.push(resp_rx);
+            }
+            sleep(Duration::from_millis(10)).await;
+            drop(guard);
+
+            let mut answered = 0;
+            for reply in replies {
+                if let Ok(outcome) = reply.await {
+                    assert_eq!(StepDownOutcome::NotLeader(ElectionState::Follower), outcome);
+                    answered += 1;
+                }
+            }
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            answered
+        });
+
+        let (answered, _) = tokio::join!(flood, server.run(),);
+
+        // Two ran, two waited for a slot, and the other six were dropped.
+        assert_eq!(4, answered.unwrap());
+        let in_flight = in_flight.lock().unwrap();
+        assert_eq!(4, in_flight.len());
+        assert!(in_flight.iter().all(|&count| count <= 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "max_in_flight_handlers must be at least 1")]
+    fn zero_handler_limit_is_rejected() {
+        Server::with_config(
+            0,
+            ServerConfig {
+                max_in_flight_handlers: 0,
+                ..ServerConfig::default()
+            },
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooded_node_still_shuts_down() {
+        let config = ServerConfig {
+            max_in_flight_handlers: 1,
+            ..ServerConfig::default()
+        };
+        let mut server = Server::with_config(0, config);
+
+        let tx = server.tx.clone();
+        let state = server.state.clone();
+        let shut_down = tokio::spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            // Every StepDown handler is parked on the lock or on a slot.
+            let _guard = state.lock().await;
+            for _ in 0..5 {
+                let (resp_tx, _resp_rx) = oneshot::channel();
+                let _ = tx.send(Command::StepDown { resp: resp_tx }).await;
+            }
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let _ = tx.send(Command::ShutDown { resp: Some(ack_tx) }).await;
+            timeout(Duration::from_secs(1), ack_rx).await
+        });
+
+        let (shut_down, _) = tokio::join!(shut_down, server.run(),);
+
+        assert!(matches!(shut_down.unwrap(), Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn shut_down_acknowledges_after_loop_stops() {
+        let mut server = Server::new(0);
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_node(9, silent_tx);
+
+        let tx = server.tx.clone();
+        let state = server.state.clone();
+        let shut_down = tokio::spawn(async move {
+            let (resp_tx, _resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let _ = tx.send(Command::ShutDown { resp: Some(ack_tx) }).await;
+            ack_rx.await.unwrap();
+            // Only the test's reference and ours are left.
+            (Arc::strong_count(&state), tx.is_closed())
+        });
+
+        let (shut_down, _) = tokio::join!(shut_down, server.run(),);
+
+        assert_eq!((2, true), shut_down.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_same_term_response_keeps_leader() {
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        // Answers well after the majority is in, with a same-term denial.
+        let (late_tx, mut late_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(cmd) = late_rx.recv().await {
+                if let Command::Vote { request, resp } = cmd {
+                    sleep(VOTE_RPC_TIMEOUT / 2).await;
+                    let _ = resp.send(VoteResponse {
+                        term: request.term,
+                        vote_granted: false,
+                        deny_reason: Some(VoteDenial::AlreadyVoted),
+                    });
+                }
+            }
+        });
+        let (silent_tx, _silent_rx) = mpsc::channel(1);
+        server.add_nodes(vec![
+            (1, node_1.tx.clone()),
+            (2, node_2.tx.clone()),
+            (3, late_tx),
+            (4, silent_tx),
+        ]);
+
+        let tx = server.tx.clone();
+        let node_1_tx = node_1.tx.clone();
+        let node_2_tx = node_2.tx.clone();
+        let state = server.state.clone();
+        let election = tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = resp_rx.await.unwrap();
+            let elapsed = started.elapsed();
+
+            sleep(VOTE_RPC_TIMEOUT * 2).await;
+            let still_leader = state.lock().await.election_state == ElectionState::Leader;
+
+            for node in &[&tx, &node_1_tx, &node_2_tx] {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            (won, elapsed, still_leader)
+        });
+
+        let (election, ..) = tokio::join!(election, server.run(), node_1.run(), node_2.run(),);
+
+        let (won, elapsed, still_leader) = election.unwrap();
+        assert!(won);
+        assert!(elapsed < VOTE_RPC_TIMEOUT / 2);
+        assert!(still_leader);
+        assert_eq!(1, server.state.lock().await.current_term);
+    }
+
+    #[tokio::test]
+    async fn election_is_audited_in_order() {
+        let audit_sink = Arc::new(MemoryAuditSink::default());
+        let config = ServerConfig {
+            audit_sink: Some(audit_sink.clone()),
+            ..ServerConfig::default()
+        };
+        let cluster = Cluster::with_config(3, config);
+        let node_0 = cluster.node(0).unwrap();
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = node_0.send(Command::ForceElection { resp: resp_tx }).await;
+        let _ = resp_rx.await;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = node_0.send(Command::StepDown { resp: resp_tx }).await;
+        let _ = resp_rx.await;
+        cluster.shut_down().await;
+
+        let role = |old_role, new_role| AuditEvent::Role {
+            node_id: 0,
+            term: 1,
+            old_role,
+            new_role,
+        };
+        let expected = vec![
+            AuditEvent::Term {
+                node_id: 0,
+                old_term: 0,
+                new_term: 1,
+                cause: TermChange::ElectionStarted,
+            },
+            role(ElectionState::Follower, ElectionState::Candidate),
+            role(ElectionState::Candidate, ElectionState::Leader),
+            role(ElectionState::Leader, ElectionState::Follower),
+        ];
+        // The sink is shared, so it also holds the voters' term changes.
+        let recorded: Vec<AuditEvent> = audit_sink
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    AuditEvent::Term { node_id: 0, .. } | AuditEvent::Role { node_id: 0, .. }
+                )
+            })
+            .cloned()
+            .collect();
+        assert_eq!(expected, recorded);
+    }
+
+    #[test]
+    fn file_audit_sink_appends_a_line_per_event() {
+        let path = std::env::temp_dir().join(format!("rs-raft-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let events = vec![
+            AuditEvent::Term {
+                node_id: 0,
+                old_term: 0,
+                new_term: 1,
+                cause: TermChange::ElectionStarted,
+            },
+            AuditEvent::Role {
+                node_id: 0,
+                term: 1,
+                old_role: ElectionState::Follower,
+                new_role: ElectionState::Candidate,
+            },
+        ];
+
+        let audit_sink = FileAuditSink::open(&path).unwrap();
+        for event in &events {
+            audit_sink.record(event);
+        }
+        // Waits for the writer to flush.
+        drop(audit_sink);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(events.len(), lines.len());
+        for (line, event) in lines.iter().zip(&events) {
+            let (at, recorded) = line.split_once(' ').unwrap();
+            assert!(at.parse::<u128>().is_ok());
+            assert_eq!(format!("{:?}", event), recorded);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_higher_term_response_demotes_leader() {
+        let mut server = Server::new(0);
+        let mut node_1 = Server::new(1);
+        let mut node_2 = Server::new(2);
+        // Answers after the majority is in, from a newer term.
+        let (late_tx, mut late_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(cmd) = late_rx.recv().await {
+                if let Command::Vote { request, resp } = cmd {
+                    sleep(VOTE_RPC_TIMEOUT / 2).await;
+                    let _ = resp.send(VoteResponse {
+                        term: request.term + 1,
+                        vote_granted: false,
+                        deny_reason: Some(VoteDenial::StaleTerm),
+                    });
+                }
+            }
+        });
+        server.add_nodes(vec![
+            (1, node_1.tx.clone()),
+            (2, node_2.tx.clone()),
+            (3, late_tx),
+        ]);
+
+        let tx = server.tx.clone();
+        let node_1_tx = node_1.tx.clone();
+        let node_2_tx = node_2.tx.clone();
+        let state = server.state.clone();
+        let election = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let won = resp_rx.await.unwrap();
+            let leader_at_first = state.lock().await.election_state == ElectionState::Leader;
+
+            sleep(VOTE_RPC_TIMEOUT).await;
+            for node in &[&tx, &node_1_tx, &node_2_tx] {
+                let _ = node.send(Command::ShutDown { resp: None }).await;
+            }
+            (won, leader_at_first)
+        });
+
+        let (election, ..) = tokio::join!(election, server.run(), node_1.run(), node_2.run(),);
+
+        assert_eq!((true, true), election.unwrap());
+        let state = server.state.lock().await;
+        assert_eq!(ElectionState::Follower, state.election_state);
+        assert_eq!(2, state.current_term);
+        assert_eq!(None, state.voted_for);
+    }
+
+    #[tokio::test]
+    async fn higher_term_response_is_adopted_after_moving_on() {
+        let mut server = Server::new(0);
+        let (peer_tx, mut peer_rx) = mpsc::channel(1);
+        server.add_node(1, peer_tx);
+
+        let tx = server.tx.clone();
+        let election = tokio::spawn(async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+            let (request, resp) = match peer_rx.recv().await {
+                Some(Command::Vote { request, resp }) => (request, resp),
+                _ => panic!("expected a vote request"),
+            };
+            assert_eq!(1, request.term);
+
+            // Node 0 moves on to term 2 by voting for someone else...
+            let (vote_tx, vote_rx) = oneshot::channel();
+            let request = VoteRequest {
+                term: 2,
+                candidate_id: 2,
+            };
+            let _ = tx
+                .send(Command::Vote {
+                    request,
+                    resp: vote_tx,
+                })
+                .await;
+            assert!(vote_rx.await.unwrap().vote_granted);
+
+            // ...before its term 1 request is answered from term 3.
+            let _ = resp.send(VoteResponse {
+                term: 3,
+                vote_granted: false,
+                deny_reason: Some(VoteDenial::StaleTerm),
+            });
+            let won = resp_rx.await.unwrap();
+            let _ = tx.send(Command::ShutDown { resp: None }).await;
+            won
+        });
+
+        let (election, _) = tokio::join!(election, server.run(),);
+
+        assert!(!election.unwrap());
+        let state = server.state.lock().await;
+        assert_eq!(ElectionState::Follower, state.election_state);
+        assert_eq!(3, state.current_term);
+        assert_eq!(None, state.voted_for);
+    }
+
+    /// xorshift64, so a failing schedule can be replayed from its seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[tokio::test]
+    async fn randomized_schedules_keep_election_safety() {
+        for seed in 1..=50u64 {
+            let mut rng = Rng(seed);
+            let size = 3 + rng.below(3) as i64;
+            let cluster = Cluster::new(size);
+            let mut leaders = std::collections::HashMap::new();
+            let mut terms = vec![0; size as usize];
+
+            for step in 0..10 {
+                let mut replies = vec![];
+                for tx in &cluster.nodes {
+                    match rng.below(4) {
+                        0 => {
+                            let (resp_tx, resp_rx) = oneshot::channel();
+                            let _ = tx.send(Command::ForceElection { resp: resp_tx }).await;
+                            replies.push(tokio::spawn(async move {
+                                let _ = resp_rx.await;
+                            }));
+                        }
+                        1 => {
+                            let (resp_tx, resp_rx) = oneshot::channel();
+                            let _ = tx.send(Command::StepDown { resp: resp_tx }).await;
+                            replies.push(tokio::spawn(async move {
+                                let _ = resp_rx.await;
+                            }));
+                        }
+                        _ => {}
+                    }
+                }
+                for reply in replies {
+                    let _ = reply.await;
+                }
+
+                // Election Safety: at most one leader per term, ever. And no
+                // node's term ever goes backwards.
+                for (id, state) in cluster.states.iter().enumerate() {
+                    let state = state.lock().await;
+                    assert!(
+                        state.current_term >= terms[id],
+                        "node {} went from term {} to {} (seed {}, step {}, size {})",
+                        id,
+                        terms[id],
+                        state.current_term,
+                        seed,
+                        step,
+                        size
+                    );
+                    terms[id] = state.current_term;
+                    if state.election_state == ElectionState::Leader {
+                        let leader = *leaders.entry(state.current_term).or_insert(id);
+                        assert_eq!(
+                            leader, id,
+                            "two leaders in term {} (seed {}, step {}, size {})",
+                            state.current_term, seed, step, size
+                        );
+                    }
+                }
+            }
+
+            cluster.shut_down().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn vote_waves_stop_at_majority() {
+        let config = ServerConfig {
+            vote_fan_out: VoteFanOut::Waves(3),
+            ..ServerConfig::default()
+        };
+        let cluster = Cluster::with_config(7, config);
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = cluster
+            .node(0)
+            .unwrap()
+            .send(Command::ForceElection { resp: resp_tx })
+            .await;
+
+        assert!(resp_rx.await.unwrap());
+        assert_eq!(Some(0), cluster.leader().await);
+        // The first wave alone made a majority of seven, so the rest were
+        // never asked and never saw the new term.
+        let mut terms = vec![];
+        for state in &cluster.states {
+            terms.push(state.lock().await.current_term);
+        }
+        assert_eq!(vec![1, 1, 1, 1, 0, 0, 0], terms);
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test]
+    async fn cluster_elects_forced_leader() {
+        let cluster = Cluster::new(3);
+        assert_eq!(None, cluster.leader().await);
+        assert!(cluster.node(3).is_none());
+        assert!(cluster.node(-1).is_none());
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let _ = cluster
+            .node(1)
+            .unwrap()
+            .send(Command::ForceElection { resp: resp_tx })
+            .await;
+
+        assert!(resp_rx.await.unwrap());
+        assert_eq!(Some(1), cluster.leader().await);
+        cluster.shut_down().await;
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::{Arc, Mutex};
+    use loom::thread;
+
+    fn vote(state: &Arc<Mutex<State>>, term: i64, candidate_id: i64) -> (i64, bool) {
+        let request = VoteRequest { term, candidate_id };
+        let response = state.lock().unwrap().vote(0, &request);
+        (candidate_id, response.vote_granted)
+    }
+
+    #[test]
+    fn at_most_one_vote_granted_per_term() {
+        loom::model(|| {
+            let state = Arc::new(Mutex::new(State {
+                election_state: ElectionState::Follower,
+                current_term: 0,
+                voted_for: None,
+                campaign_term: None,
+                kind: NodeKind::Voter,
+                config: ServerConfig::default(),
+            }));
+
+            let vote_1 = {
+                let state = state.clone();
+                thread::spawn(move || vote(&state, 1, 1))
+            };
+            let vote_2 = {
+                let state = state.clone();
+                thread::spawn(move || vote(&state, 1, 2))
+            };
+            // Stands in for an append handler: it only ever learns the
+            // leader's term and steps down, clearing the vote for the new term.
+            let append = {
+                let state = state.clone();
+                thread::spawn(move || {
+                    let mut state = state.lock().unwrap();
+                    if 1 > state.current_term {
+                        state.election_state = ElectionState::Follower;
+                        state.voted_for = None;
+                        state.set_term(0, 1, TermChange::HigherTermSeen);
+                    }
+                })
+            };
+
+            let votes = vec![vote_1.join().unwrap(), vote_2.join().unwrap()];
+            append.join().unwrap();
+
+            let granted: Vec<i64> = votes
+                .into_iter()
+                .filter(|(_, granted)| *granted)
+                .map(|(candidate_id, _)| candidate_id)
+                .collect();
+            assert!(granted.len() <= 1, "granted {:?} in term 1", granted);
+
+            let state = state.lock().unwrap();
+            assert_eq!(1, state.current_term);
+            if let Some(&candidate_id) = granted.first() {
+                assert_eq!(Some(candidate_id), state.voted_for);
+            }
+        });
+    }
 }